@@ -1,17 +1,37 @@
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use cargo::Config;
-use cargo::core::{Package,PackageSet};
+use cargo::core::{PackageSet,Resolve,Workspace};
 use cargo::core::dependency::Kind;
+use cargo::ops;
 use cargo::util::{human, hex, CargoResult, ChainError, Sha256};
+use curl::easy::Easy;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use git2::{self, Repository};
+use rayon::prelude::*;
 use rustc_serialize::json;
 use rustc_serialize::hex::ToHex;
+use tar::{Builder, EntryType, Header};
 use url::Url;
 
+/// Options controlling a vendor pass, mirroring upstream cargo's `vendor.rs`.
+pub struct VendorOptions<'a> {
+    /// Leave crates already present in the destination in place rather than
+    /// pruning everything the current `PackageSet` no longer references.
+    pub no_delete: bool,
+    /// Where the vendored registry is written.
+    pub destination: &'a Path,
+    /// Additional manifests to fold into the same registry.
+    pub extra: Vec<PathBuf>,
+    /// Also lay the index out as flat files servable over the sparse HTTP
+    /// protocol, in addition to the git-backed index.
+    pub sparse: bool,
+}
+
 #[derive(RustcEncodable)]
 struct RegistryPackage {
     name: String,
@@ -20,6 +40,7 @@ struct RegistryPackage {
     features: HashMap<String, Vec<String>>,
     cksum: String,
     yanked: Option<bool>,
+    source: Option<String>,
 }
 
 #[derive(RustcEncodable)]
@@ -35,10 +56,12 @@ struct RegistryDependency {
 
 pub fn vendor<'cfg>(config: &Config,
               packages: &PackageSet<'cfg>,
-              into: &Path) -> CargoResult<()> {
+              resolve: &Resolve,
+              opts: &VendorOptions) -> CargoResult<()> {
+    let into = opts.destination;
     let index = into.join("index");
     let download = into.join("cache");
-    try!(fs::create_dir(&download));
+    try!(fs::create_dir_all(&download));
     let index_url = try!(Url::from_file_path(&index).map_err(|()| {
         human(format!("failed to convert {:?} to a URL", index))
     }));
@@ -50,10 +73,30 @@ pub fn vendor<'cfg>(config: &Config,
         f.write_all(format!(r#"{{"dl":"{}","api":""}}"#, dl_url).as_bytes())
     }));
 
-    for package_id in packages.package_ids() {
-        let package = try!(packages.get(&package_id));
-        try!(vendor_package(config, package, &index, &download).chain_error(|| {
-            human(format!("failed to vendor `{}`", package.package_id()))
+    // Vendor the primary package set plus any extra workspaces into one merged
+    // registry, deduplicating crates shared across workspaces by their
+    // `(name, version, checksum)` triple (the pinned revision stands in for the
+    // checksum of git crates). `referenced` drives pruning; `seen` ensures each
+    // unique crate is only packed once.
+    let mut referenced = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut blobs = HashSet::new();
+    try!(vendor_set(config, packages, resolve, &index, &download,
+                    &mut referenced, &mut seen, &mut blobs));
+    for manifest in &opts.extra {
+        let ws = try!(Workspace::new(manifest, config));
+        let (packages, resolve) = try!(ops::resolve_ws(&ws).chain_error(|| {
+            human(format!("failed to resolve `{}`", manifest.display()))
+        }));
+        try!(vendor_set(config, &packages, &resolve, &index, &download,
+                        &mut referenced, &mut seen, &mut blobs));
+    }
+
+    // Unless asked to keep everything, drop index entries and cache files that
+    // the current set of packages no longer references.
+    if !opts.no_delete {
+        try!(prune(&index, &download, &referenced, &blobs).chain_error(|| {
+            human("failed to prune stale vendor entries")
         }));
     }
 
@@ -67,74 +110,592 @@ pub fn vendor<'cfg>(config: &Config,
     index = \"{}\"
 
 ", index_url);
+
+    // Optionally mirror the index as a flat, sparse-servable tree. A sparse
+    // HTTP index avoids cloning a whole git index and can be served by any
+    // static file server, with clients fetching `{prefix}/{name}` directly.
+    if opts.sparse {
+        let sparse = into.join("sparse");
+        try!(write_sparse_index(&index, &sparse, &dl_url).chain_error(|| {
+            human("failed to write the sparse index")
+        }));
+        let sparse_url = try!(Url::from_directory_path(&sparse).map_err(|()| {
+            human(format!("failed to convert {:?} to a URL", sparse))
+        }));
+        println!("Or, to serve the index over the sparse protocol, use:
+
+    [source.crates-io]
+    replace-with = \"vendored-sparse\"
+
+    [source.vendored-sparse]
+    registry = \"sparse+{}\"
+
+(replace `sparse+file://` with `sparse+http://` when serving the directory
+over HTTP)
+", sparse_url);
+    }
     Ok(())
 }
 
-fn vendor_package(config: &Config,
-                  package: &Package,
-                  index: &Path,
-                  download: &Path) -> CargoResult<()> {
-    let package_id = package.package_id();
-    let source_id = package_id.source_id();
-
-    // Copy the crate file into place
-    let crate_file = config.registry_cache_path().join({
-        let hash = hex::short_hash(source_id);
-        let ident = source_id.url().host().unwrap().to_string();
-        format!("{}-{}", ident, hash)
-    }).join({
-        format!("{}-{}.crate", package_id.name(), package_id.version())
-    });
-    let dst = download.join(package_id.name())
-                      .join(package_id.version().to_string())
-                      .join("download");
-    try!(fs::create_dir_all(dst.parent().unwrap()));
-    try!(fs::copy(&crate_file.clone().into_path_unlocked(), &dst).chain_error(|| {
-        human(format!("cached crate file `{}` doesn't exist for `{}`",
-                      crate_file.display(), package_id))
+// Mirror the git index's `name`-keyed files into a flat tree plus a sparse
+// `config.json` carrying both `dl` and `api` entries. The per-name files are
+// already newline-delimited JSON, exactly what a sparse client GETs.
+fn write_sparse_index(index: &Path,
+                      sparse: &Path,
+                      dl_url: &Url) -> CargoResult<()> {
+    if sparse.exists() {
+        try!(fs::remove_dir_all(sparse));
+    }
+    try!(fs::create_dir_all(sparse));
+    try!(copy_index_files(index, index, sparse));
+    let api_url = try!(Url::from_directory_path(sparse).map_err(|()| {
+        human(format!("failed to convert {:?} to a URL", sparse))
     }));
+    try!(File::create(&sparse.join("config.json")).and_then(|mut f| {
+        f.write_all(format!(r#"{{"dl":"{}","api":"{}"}}"#, dl_url, api_url).as_bytes())
+    }));
+    Ok(())
+}
 
-    // Create an entry in the index for this package
-    let mut s = Sha256::new();
-    let mut c = Vec::new();
-    try!(File::open(&dst).and_then(|mut f| f.read_to_end(&mut c)));
-    s.update(&c);
-    let package = RegistryPackage {
-        name: package_id.name().to_string(),
-        vers: package_id.version().to_string(),
-        features: package.summary().features().clone(),
+fn copy_index_files(root: &Path, dir: &Path, sparse: &Path) -> CargoResult<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name == ".git" || name == "config.json" {
+            continue
+        }
+        if try!(fs::metadata(&path)).is_dir() {
+            try!(copy_index_files(root, &path, sparse));
+            continue
+        }
+        let rel = path.strip_prefix(root).unwrap();
+        let dst = sparse.join(rel);
+        try!(fs::create_dir_all(dst.parent().unwrap()));
+        try!(fs::copy(&path, &dst));
+    }
+    Ok(())
+}
+
+// Everything needed to pack one crate, gathered serially so the parallel phase
+// never has to touch the non-`Send` cargo `Package`/`Config` types.
+struct PackageWork {
+    name: String,
+    vers: String,
+    dst: PathBuf,
+    source: PackageSource,
+    expected: Option<String>,
+    features: HashMap<String, Vec<String>>,
+    deps: Vec<RegistryDependency>,
+}
+
+enum PackageSource {
+    // Registry crates live in the local cache, falling back to the download URL.
+    Registry { crate_file: PathBuf, url: String },
+    // Git crates are cloned at the pinned revision and packed on the fly.
+    Git(GitWork),
+}
+
+// A resolved git source plus its pinned revision, ready to check out and pack.
+struct GitWork {
+    url: String,
+    rev: String,
+    ident: String,
+    prefix: String,
+    source: String,
+}
+
+// Vendor every package in one resolved set, skipping crates already packed for
+// another workspace. A crate is considered identical when its
+// `(name, version, checksum)` triple matches one already seen, with the pinned
+// revision in place of the checksum for git crates. The expensive
+// copy/hash work is fanned out across a thread pool; the index entries it
+// produces are written afterwards so the index files stay consistent.
+fn vendor_set<'cfg>(config: &Config,
+                    packages: &PackageSet<'cfg>,
+                    resolve: &Resolve,
+                    index: &Path,
+                    download: &Path,
+                    referenced: &mut HashSet<(String, String)>,
+                    seen: &mut HashSet<(String, String, String)>,
+                    blobs: &mut HashSet<String>)
+                    -> CargoResult<()> {
+    // Gather the per-package work serially (cargo's `Package` is not `Send`).
+    let mut work = Vec::new();
+    for package_id in packages.package_ids() {
+        let name = package_id.name().to_string();
+        let vers = package_id.version().to_string();
+        let source_id = package_id.source_id();
+        let expected = match resolve.checksums().get(&package_id) {
+            Some(&Some(ref c)) => Some(c.clone()),
+            _ => None,
+        };
+        let dst = download.join(&name).join(&vers).join("download");
+        let source = if source_id.is_git() {
+            let rev = try!(source_id.precise().chain_error(|| {
+                human(format!("git source for `{}` is not pinned to a revision",
+                              package_id))
+            }));
+            PackageSource::Git(GitWork {
+                url: source_id.url().to_string(),
+                rev: rev.to_string(),
+                ident: {
+                    let hash = hex::short_hash(source_id);
+                    let host = source_id.url().host_str().unwrap_or("");
+                    format!("{}-{}", host, hash)
+                },
+                prefix: format!("{}-{}", name, vers),
+                source: source_id.to_url().to_string(),
+            })
+        } else {
+            let crate_file = config.registry_cache_path().join({
+                let hash = hex::short_hash(source_id);
+                let ident = source_id.url().host().unwrap().to_string();
+                format!("{}-{}", ident, hash)
+            }).join(format!("{}-{}.crate", name, vers));
+            // The download endpoint is *not* the index host: crates.io serves
+            // its index from github.com but its crates from crates.io, so map
+            // the default registry accordingly rather than reusing the index
+            // host (which would 404 every download).
+            let base = if source_id.is_default_registry() {
+                "https://crates.io".to_string()
+            } else {
+                let host = try!(source_id.url().host_str().chain_error(|| {
+                    human(format!("registry source for `{}` has no host", package_id))
+                }));
+                format!("https://{}", host)
+            };
+            PackageSource::Registry {
+                crate_file: crate_file.into_path_unlocked(),
+                url: format!("{}/api/v1/crates/{}/{}/download", base, name, vers),
+            }
+        };
+        // Dedup identical crates across workspaces. Registry crates are keyed by
+        // their locked checksum; git crates have none, so key them by the pinned
+        // revision instead. Crates that differ only in this key are each packed,
+        // but they share one `cache/{name}/{version}` slot and one index line,
+        // so the one written last replaces the other.
+        let discriminator = match source {
+            PackageSource::Git(ref git) => git.rev.clone(),
+            PackageSource::Registry { .. } => {
+                expected.clone().unwrap_or(String::new())
+            }
+        };
+        if !seen.insert((name.clone(), vers.clone(), discriminator)) {
+            continue
+        }
+        let package = try!(packages.get(&package_id));
+        work.push(PackageWork {
+            name: name.clone(),
+            vers: vers.clone(),
+            dst: dst,
+            source: source,
+            expected: expected,
+            features: package.summary().features().clone(),
+            deps: package.dependencies().iter().map(|d| {
+                RegistryDependency {
+                    name: d.name().to_string(),
+                    req: d.version_req().to_string(),
+                    features: d.features().to_vec(),
+                    optional: d.is_optional(),
+                    default_features: d.uses_default_features(),
+                    target: d.platform().map(|t| t.to_string()),
+                    kind: match d.kind() {
+                        Kind::Normal => "normal".to_string(),
+                        Kind::Build => "build".to_string(),
+                        Kind::Development => "dev".to_string(),
+                    },
+                }
+            }).collect(),
+        });
+        referenced.insert((name, vers));
+    }
+
+    // Fetch each unique git source once, serially, before the parallel phase.
+    // Multiple crates from one repo share a bare db under `cache/.git-db`, and
+    // concurrent `init_bare`/`fetch` into the same repository would race.
+    let mut fetched = HashSet::new();
+    for w in &work {
+        if let PackageSource::Git(ref git) = w.source {
+            if fetched.insert(git.ident.clone()) {
+                try!(fetch_git_db(git, download).chain_error(|| {
+                    human(format!("failed to fetch `{}`", git.url))
+                }));
+            }
+        }
+    }
+
+    // Pack and hash every crate in parallel, content-addressing the blobs.
+    let blobs_dir = download.join(".blobs");
+    let entries = try!(work.par_iter().map(|w| {
+        pack_package(w, &blobs_dir)
+    }).collect::<CargoResult<Vec<RegistryPackage>>>());
+
+    // Write the collected index entries serially to keep the files consistent.
+    for package in &entries {
+        let dst = match package.name.len() {
+            1 => index.join("1").join(&package.name),
+            2 => index.join("2").join(&package.name),
+            3 => index.join("3").join(&package.name[..1]).join(&package.name),
+            _ => index.join(&package.name[..2])
+                      .join(&package.name[2..4])
+                      .join(&package.name),
+        };
+        try!(fs::create_dir_all(dst.parent().unwrap()));
+        let json = json::encode(package).unwrap();
+        try!(update_index(&dst, &package.vers, &json));
+        // Record the live blob hash so pruning can sweep by reference, not by
+        // link count (downloads may be symlinks or copies, not hard links).
+        blobs.insert(package.cksum.clone());
+    }
+    Ok(())
+}
+
+// Pack a single crate into the content-addressed cache and return its index
+// entry. Runs on a worker thread, so it only touches `Send` data.
+fn pack_package(w: &PackageWork, blobs: &Path) -> CargoResult<RegistryPackage> {
+    try!(fs::create_dir_all(w.dst.parent().unwrap()));
+
+    // Stage the raw `.crate` bytes next to their final location before hashing.
+    let staging = w.dst.with_extension("part");
+    let source = match w.source {
+        PackageSource::Git(ref git) => {
+            try!(vendor_git(git, &staging).chain_error(|| {
+                human(format!("failed to check out git source for `{}`", w.name))
+            }));
+            Some(git.source.clone())
+        }
+        PackageSource::Registry { ref crate_file, ref url } => {
+            if crate_file.exists() {
+                try!(fs::copy(crate_file, &staging).chain_error(|| {
+                    human(format!("failed to copy cached crate file `{}` for `{}`",
+                                  crate_file.display(), w.name))
+                }));
+            } else {
+                // On a fresh machine the cache may never have been populated.
+                try!(download_crate(url, &staging).chain_error(|| {
+                    human(format!("failed to download `{}` from `{}`", w.name, url))
+                }));
+            }
+            None
+        }
+    };
+
+    // Stream the bytes through SHA-256 rather than slurping the whole file.
+    let cksum = try!(hash_file(&staging));
+
+    // Never trust whatever happens to be on disk: the crate has to match the
+    // checksum `Cargo.lock` pinned for it.
+    if let Some(ref expected) = w.expected {
+        try!(validate_checksum(&cksum, expected,
+                               &format!("{} v{}", w.name, w.vers)));
+    }
+
+    // Store the blob once under its hash and link it into the download slot so
+    // identical blobs pulled by multiple workspaces are never duplicated.
+    try!(link_blob(&staging, &cksum, blobs, &w.dst));
+
+    Ok(RegistryPackage {
+        name: w.name.clone(),
+        vers: w.vers.clone(),
+        features: w.features.clone(),
         yanked: Some(false),
-        cksum: s.finish().to_hex(),
-        deps: package.dependencies().iter().map(|d| {
+        cksum: cksum,
+        source: source,
+        deps: w.deps.iter().map(|d| {
             RegistryDependency {
-                name: d.name().to_string(),
-                req: d.version_req().to_string(),
-                features: d.features().to_vec(),
-                optional: d.is_optional(),
-                default_features: d.uses_default_features(),
-                target: d.platform().map(|t| t.to_string()),
-                kind: match d.kind() {
-                    Kind::Normal => "normal".to_string(),
-                    Kind::Build => "build".to_string(),
-                    Kind::Development => "dev".to_string(),
-                },
+                name: d.name.clone(),
+                req: d.req.clone(),
+                features: d.features.clone(),
+                optional: d.optional,
+                default_features: d.default_features,
+                target: d.target.clone(),
+                kind: d.kind.clone(),
             }
         }).collect(),
+    })
+}
+
+// Stream a file through `Sha256` and return its lowercase hex digest.
+fn hash_file(path: &Path) -> CargoResult<String> {
+    let mut s = Sha256::new();
+    let mut f = try!(File::open(path));
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let n = try!(f.read(&mut buf));
+        if n == 0 {
+            break
+        }
+        s.update(&buf[..n]);
+    }
+    Ok(s.finish().to_hex())
+}
+
+// Move the staged blob into the content-addressed store (keyed by its hash) if
+// it isn't already there, then hard-link it into `dst`, falling back to a
+// symlink and finally a plain copy when linking across the store isn't allowed.
+fn link_blob(staging: &Path,
+             cksum: &str,
+             blobs: &Path,
+             dst: &Path) -> CargoResult<()> {
+    try!(fs::create_dir_all(blobs));
+    let blob = blobs.join(cksum);
+    if blob.exists() {
+        try!(fs::remove_file(staging));
+    } else {
+        try!(fs::rename(staging, &blob));
+    }
+    if dst.exists() {
+        try!(fs::remove_file(dst));
+    }
+    fs::hard_link(&blob, dst)
+        .or_else(|_| ::std::os::unix::fs::symlink(&blob, dst))
+        .or_else(|_| fs::copy(&blob, dst).map(|_| ()))
+        .map_err(|e| human(format!("failed to link `{}`: {}", dst.display(), e)))
+}
+
+// Rewrite a `name`-keyed index file so re-running vendor is idempotent: the
+// line for a matching version is replaced in place and genuinely new versions
+// are appended, rather than blindly appending a duplicate every time.
+fn update_index(dst: &Path, vers: &str, json: &str) -> CargoResult<()> {
+    let mut lines = Vec::new();
+    if dst.exists() {
+        let mut contents = String::new();
+        try!(File::open(dst).and_then(|mut f| f.read_to_string(&mut contents)));
+        lines.extend(contents.lines().map(|l| l.to_string()));
+    }
+    let mut replaced = false;
+    for line in lines.iter_mut() {
+        if line_version(line).as_ref().map(|v| &v[..]) == Some(vers) {
+            *line = json.to_string();
+            replaced = true;
+            break;
+        }
+    }
+    if !replaced {
+        lines.push(json.to_string());
+    }
+    let mut f = try!(File::create(dst));
+    for line in &lines {
+        try!(writeln!(f, "{}", line));
+    }
+    Ok(())
+}
+
+// Pull the `vers` field out of an index line without a full struct decode.
+fn line_version(line: &str) -> Option<String> {
+    json::Json::from_str(line).ok().and_then(|j| {
+        j.find("vers").and_then(|v| v.as_string().map(|s| s.to_string()))
+    })
+}
+
+// Remove index lines and cache directories for `(name, version)` pairs that the
+// current `PackageSet` no longer references.
+fn prune(index: &Path,
+         download: &Path,
+         referenced: &HashSet<(String, String)>,
+         blob_hashes: &HashSet<String>) -> CargoResult<()> {
+    // Cache: `cache/{name}/{version}`.
+    if download.exists() {
+        for name_entry in try!(fs::read_dir(download)) {
+            let name_dir = try!(name_entry).path();
+            if !try!(fs::metadata(&name_dir)).is_dir() {
+                continue
+            }
+            let name = name_dir.file_name().unwrap().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue
+            }
+            for vers_entry in try!(fs::read_dir(&name_dir)) {
+                let vers_dir = try!(vers_entry).path();
+                let vers = vers_dir.file_name().unwrap()
+                                   .to_string_lossy().into_owned();
+                if !referenced.contains(&(name.clone(), vers)) {
+                    try!(fs::remove_dir_all(&vers_dir));
+                }
+            }
+        }
+    }
+
+    // Content-addressed store: drop any blob whose hash is not referenced by a
+    // crate in the current set. Liveness is tracked explicitly rather than
+    // inferred from link count, since a download slot may be a symlink or a
+    // plain copy (not a hard link) on filesystems that disallow hard-linking.
+    let blobs = download.join(".blobs");
+    if blobs.exists() {
+        for entry in try!(fs::read_dir(&blobs)) {
+            let path = try!(entry).path();
+            let hash = path.file_name().unwrap().to_string_lossy().into_owned();
+            if !blob_hashes.contains(&hash) {
+                try!(fs::remove_file(&path));
+            }
+        }
+    }
+
+    // Index: prune stale version lines from every `name`-keyed file.
+    try!(prune_index_dir(index, referenced));
+    Ok(())
+}
+
+fn prune_index_dir(dir: &Path,
+                   referenced: &HashSet<(String, String)>) -> CargoResult<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name == ".git" || name == "config.json" {
+            continue
+        }
+        if try!(fs::metadata(&path)).is_dir() {
+            try!(prune_index_dir(&path, referenced));
+            continue
+        }
+        // A leaf is a `name`-keyed file; its name is the crate name.
+        let mut contents = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)));
+        let kept: Vec<String> = contents.lines().filter(|line| {
+            match line_version(line) {
+                Some(vers) => referenced.contains(&(name.clone(), vers)),
+                None => true,
+            }
+        }).map(|l| l.to_string()).collect();
+        if kept.is_empty() {
+            try!(fs::remove_file(&path));
+        } else {
+            let mut f = try!(File::create(&path));
+            for line in &kept {
+                try!(writeln!(f, "{}", line));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Clone or update the bare db for a git source, fetching the remote once. Run
+// serially before the parallel packing phase so crates sharing a repo don't
+// fetch into the same db concurrently. Modelled after cargo-fetcher's `via_git`,
+// which clones into a temp dir and checks out the resolved revision.
+fn fetch_git_db(git: &GitWork, download: &Path) -> CargoResult<()> {
+    let db = download.join(".git-db").join(&git.ident);
+    let db_repo = if db.exists() {
+        try!(Repository::open_bare(&db))
+    } else {
+        try!(fs::create_dir_all(&db));
+        try!(Repository::init_bare(&db))
     };
-    let json = json::encode(&package).unwrap();
-    let dst = match package_id.name().len() {
-        1 => index.join("1").join(package_id.name()),
-        2 => index.join("2").join(package_id.name()),
-        3 => index.join("3").join(&package_id.name()[..1])
-                            .join(package_id.name()),
-        _ => index.join(&package_id.name()[..2])
-                  .join(&package_id.name()[2..4])
-                  .join(package_id.name()),
-    };
-    try!(fs::create_dir_all(dst.parent().unwrap()));
-    try!(OpenOptions::new().create(true).append(true).open(&dst).and_then(|mut f| {
-        write!(f, "{}\n", json)
-    }));
+    // Pull branch heads and tags: a pinned rev or tag need not be reachable
+    // from a branch head, and `vendor_git` resets to the exact object.
+    let mut remote = try!(db_repo.remote_anonymous(&git.url));
+    try!(remote.fetch(&["+refs/heads/*:refs/heads/*",
+                        "+refs/tags/*:refs/tags/*"], None, None));
+
+    // If the resolved sha still isn't present (e.g. a rev behind a PR ref),
+    // fetch that object directly so the checkout can't fail.
+    let oid = try!(git2::Oid::from_str(&git.rev));
+    if db_repo.find_object(oid, None).is_err() {
+        try!(remote.fetch(&[&git.rev[..]], None, None).chain_error(|| {
+            human(format!("failed to fetch revision `{}` from `{}`",
+                          git.rev, git.url))
+        }));
+    }
+    Ok(())
+}
+
+// Check out the pinned revision from the already-fetched bare db into a working
+// tree under `cache/` and pack it into the synthetic `.crate` tarball at `dst`.
+fn vendor_git(git: &GitWork, dst: &Path) -> CargoResult<()> {
+    let oid = try!(git2::Oid::from_str(&git.rev));
+
+    // The bare db was populated serially by `fetch_git_db`.
+    let db = dst.parent().unwrap()
+                .parent().unwrap()
+                .parent().unwrap()
+                .join(".git-db").join(&git.ident);
+
+    // A working checkout next to the other cached artefacts for this crate.
+    let checkout = dst.parent().unwrap().join("checkout");
+    if checkout.exists() {
+        try!(fs::remove_dir_all(&checkout));
+    }
+    let co_repo = try!(Repository::clone(db.to_str().unwrap(), &checkout));
+    let obj = try!(co_repo.find_object(oid, None));
+    try!(co_repo.reset(&obj, git2::ResetType::Hard, None));
+
+    // Pack the checked-out tree into a gzipped tarball so the cache entry looks
+    // exactly like a registry-sourced `.crate`: no VCS metadata, and a
+    // deterministic layout (sorted entries, zeroed mtimes) so the recorded
+    // checksum is stable across runs rather than dependent on file times.
+    let file = try!(File::create(dst));
+    let encoder = GzEncoder::new(file, Compression::Default);
+    let mut ar = Builder::new(encoder);
+    try!(append_tree(&mut ar, &checkout, Path::new(&git.prefix)));
+    try!(try!(ar.into_inner()).finish());
+    Ok(())
+}
+
+// Append a directory tree to a tarball, skipping `.git`, visiting entries in a
+// stable sorted order, and zeroing each header's mtime for reproducibility.
+fn append_tree<W: Write>(ar: &mut Builder<W>,
+                         dir: &Path,
+                         prefix: &Path) -> CargoResult<()> {
+    let mut entries: Vec<PathBuf> = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        entries.push(try!(entry).path());
+    }
+    entries.sort();
+    for path in &entries {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name == ".git" {
+            continue
+        }
+        let rel = prefix.join(&name);
+        let meta = try!(fs::metadata(path));
+        if meta.is_dir() {
+            try!(append_tree(ar, path, &rel));
+        } else {
+            let mut f = try!(File::open(path));
+            let mut header = Header::new_gnu();
+            header.set_size(meta.len());
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_entry_type(EntryType::file());
+            try!(ar.append_data(&mut header, &rel, &mut f));
+        }
+    }
+    Ok(())
+}
+
+// Fetch a crate tarball over HTTP straight into `dst`. Used as a fallback when
+// the local registry cache has no copy of the `.crate` file.
+fn download_crate(url: &str, dst: &Path) -> CargoResult<()> {
+    let mut handle = Easy::new();
+    try!(handle.url(url));
+    try!(handle.follow_location(true));
+    let mut file = try!(File::create(dst));
+    {
+        let mut transfer = handle.transfer();
+        try!(transfer.write_function(|data| {
+            file.write_all(data).map(|()| data.len()).map_err(|_| {
+                curl::easy::WriteError::Pause
+            })
+        }));
+        try!(transfer.perform());
+    }
+    let code = try!(handle.response_code());
+    if code != 200 {
+        return Err(human(format!("failed to download `{}`: status code {}",
+                                 url, code)));
+    }
+    Ok(())
+}
+
+// Compare a freshly computed SHA-256 against the one pinned in `Cargo.lock`,
+// mirroring cargo-fetcher's `validate_checksum`.
+fn validate_checksum(actual: &str,
+                     expected: &str,
+                     package: &str) -> CargoResult<()> {
+    if actual != expected {
+        return Err(human(format!("checksum mismatch for `{}`: expected {}, got {}",
+                                 package, expected, actual)));
+    }
     Ok(())
 }
 